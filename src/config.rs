@@ -0,0 +1,103 @@
+use std::env;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Edge {
+    Left,
+    #[default]
+    Right,
+    Top,
+    Bottom,
+}
+
+impl Edge {
+    pub fn is_vertical(self) -> bool {
+        matches!(self, Edge::Left | Edge::Right)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    #[serde(deserialize_with = "deserialize_icon_size")]
+    pub icon_size: u16,
+    pub panel_width: u16,
+    pub spacing: i16,
+    #[serde(deserialize_with = "deserialize_color")]
+    pub background: u32,
+    #[serde(deserialize_with = "deserialize_color")]
+    pub accent: u32,
+    pub edge: Edge,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            icon_size: 32,
+            panel_width: 32,
+            spacing: 4,
+            background: 0x000000,
+            accent: 0x3584E4,
+            edge: Edge::Right,
+        }
+    }
+}
+
+// Accepts either a "#rrggbb" / "#rrggbbaa" hex string or a plain integer, which is
+// used unchanged as 0x00RRGGBB (an integer carries no alpha byte).
+fn deserialize_color<'de, D>(deserializer: D) -> Result<u32, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum ColorValue {
+        Hex(String),
+        Rgba(u32),
+    }
+
+    match ColorValue::deserialize(deserializer)? {
+        ColorValue::Rgba(v) => Ok(v),
+        ColorValue::Hex(s) => {
+            let s = s.trim_start_matches('#');
+            let v = u32::from_str_radix(s, 16).map_err(serde::de::Error::custom)?;
+            // Drawing only ever consumes 0x00RRGGBB; drop the alpha byte from
+            // an "#rrggbbaa" string rather than let it bleed into the blue channel.
+            Ok(if s.len() == 8 { v >> 8 } else { v })
+        }
+    }
+}
+
+// load_window_icon creates an icon_size x icon_size pixmap for every icon, and
+// X rejects a pixmap with a zero dimension, so 0 is raised to 1.
+fn deserialize_icon_size<'de, D>(deserializer: D) -> Result<u16, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(u16::deserialize(deserializer)?.max(1))
+}
+
+// Loads `$XDG_CONFIG_HOME/nsp/config.toml`, falling back to `~/.config/nsp/config.toml`.
+// Missing or unparsable config silently falls back to defaults.
+pub fn load() -> Config {
+    let Some(path) = config_path() else {
+        return Config::default();
+    };
+
+    std::fs::read_to_string(&path)
+        .ok()
+        .and_then(|contents| toml::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+fn config_path() -> Option<PathBuf> {
+    let config_home = env::var("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|_| env::var("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .ok()?;
+
+    Some(config_home.join("nsp").join("config.toml"))
+}
@@ -1,5 +1,8 @@
-use xcb::{x, Connection, Xid, XidNew};
-use std::collections::HashMap;
+use xcb::{randr, x, Connection, Xid, XidNew};
+use std::collections::{HashMap, HashSet};
+
+mod config;
+use config::{Config, Edge};
 
 mod atoms {
     pub const NET_CLIENT_LIST: &[u8] = b"_NET_CLIENT_LIST";
@@ -11,11 +14,24 @@ mod atoms {
     pub const WM_PROTOCOLS: &[u8] = b"WM_PROTOCOLS";
     pub const _NET_WM_NAME: &[u8] = b"_NET_WM_NAME";
     pub const UTF8_STRING: &[u8] = b"UTF8_STRING";
+    pub const MANAGER: &[u8] = b"MANAGER";
+    pub const NET_SYSTEM_TRAY_OPCODE: &[u8] = b"_NET_SYSTEM_TRAY_OPCODE";
+    pub const XEMBED_EMBEDDED_NOTIFY: &[u8] = b"_XEMBED_EMBEDDED_NOTIFY";
+    pub const NET_ACTIVE_WINDOW: &[u8] = b"_NET_ACTIVE_WINDOW";
+    pub const NET_CURRENT_DESKTOP: &[u8] = b"_NET_CURRENT_DESKTOP";
+    pub const NET_WM_DESKTOP: &[u8] = b"_NET_WM_DESKTOP";
+    pub const NET_WM_STRUT_PARTIAL: &[u8] = b"_NET_WM_STRUT_PARTIAL";
+    pub const NET_SUPPORTED: &[u8] = b"_NET_SUPPORTED";
 }
 
-const ICON_SIZE: u16 = 32;
-const PANEL_WIDTH: u16 = 32;
-const ITEM_SPACING: i16 = 4;
+// _NET_WM_DESKTOP value meaning "show on all desktops" (EWMH)
+const DESKTOP_ALL: u32 = 0xFFFFFFFF;
+
+// freedesktop systemtray-spec: opcode carried in a _NET_SYSTEM_TRAY_OPCODE ClientMessage
+const SYSTEM_TRAY_REQUEST_DOCK: u32 = 0;
+
+// x, y, width, height, in root coordinate space
+type Rect = (i16, i16, u16, u16);
 
 struct Atoms {
     net_client_list: x::Atom,
@@ -26,6 +42,14 @@ struct Atoms {
     net_wm_state_skip_taskbar: x::Atom,
     net_wm_name: x::Atom,
     utf8_string: x::Atom,
+    manager: x::Atom,
+    net_system_tray_opcode: x::Atom,
+    xembed_embedded_notify: x::Atom,
+    net_active_window: x::Atom,
+    net_current_desktop: x::Atom,
+    net_wm_desktop: x::Atom,
+    net_wm_strut_partial: x::Atom,
+    net_supported: x::Atom,
 }
 
 #[derive(Debug, Clone)]
@@ -35,21 +59,40 @@ struct WindowData {
     title: String,
 }
 
+#[derive(Debug, Clone)]
+struct TrayIconData {
+    rect: x::Rectangle,
+}
+
 struct Panel {
     window: x::Window,
     gc: x::Gcontext,
     icon_gc: x::Gcontext,
-    height: u16,
+    highlight_gc: x::Gcontext,
+    win_width: u16,
+    win_height: u16,
     windows: HashMap<x::Window, WindowData>,
+    tray_icons: HashMap<x::Window, TrayIconData>,
+    tray_selection: x::Atom,
+    current_desktop: u32,
+    active: Option<x::Window>,
     atoms: Atoms,
+    config: Config,
 }
 
 impl Panel {
-    fn new(conn: &Connection, screen: &x::Screen) -> Self {
+    fn new(conn: &Connection, screen: &x::Screen, screen_num: i32) -> Self {
+        let config = config::load();
+
         let window = conn.generate_id();
         let gc = conn.generate_id();
         let icon_gc = conn.generate_id();
+        let highlight_gc = conn.generate_id();
+
+        let screen_width = screen.width_in_pixels();
         let screen_height = screen.height_in_pixels();
+        let output = primary_output_geometry(conn, screen.root(), (0, 0, screen_width, screen_height));
+        let (win_x, win_y, win_width, win_height) = panel_geometry(&config, output);
 
         let atoms = Atoms {
             net_client_list: intern_atom(conn, atoms::NET_CLIENT_LIST),
@@ -60,28 +103,39 @@ impl Panel {
             net_wm_state_skip_taskbar: intern_atom(conn, atoms::NET_WM_STATE_SKIP_TASKBAR),
             net_wm_name: intern_atom(conn, atoms::_NET_WM_NAME),
             utf8_string: intern_atom(conn, atoms::UTF8_STRING),
+            manager: intern_atom(conn, atoms::MANAGER),
+            net_system_tray_opcode: intern_atom(conn, atoms::NET_SYSTEM_TRAY_OPCODE),
+            xembed_embedded_notify: intern_atom(conn, atoms::XEMBED_EMBEDDED_NOTIFY),
+            net_active_window: intern_atom(conn, atoms::NET_ACTIVE_WINDOW),
+            net_current_desktop: intern_atom(conn, atoms::NET_CURRENT_DESKTOP),
+            net_wm_desktop: intern_atom(conn, atoms::NET_WM_DESKTOP),
+            net_wm_strut_partial: intern_atom(conn, atoms::NET_WM_STRUT_PARTIAL),
+            net_supported: intern_atom(conn, atoms::NET_SUPPORTED),
         };
 
-        let panel_x = (screen.width_in_pixels() - PANEL_WIDTH) as i16;
+        let tray_selection = intern_atom_create(
+            conn,
+            format!("_NET_SYSTEM_TRAY_S{}", screen_num).as_bytes(),
+        );
 
-        // Создание окна с чёрным фоном
+        // Создание окна с настроенным фоном
         conn.send_request(&x::CreateWindow {
             depth: screen.root_depth() as u8,
             wid: window,
             parent: screen.root(),
-            x: panel_x,
-            y: 0,
-            width: PANEL_WIDTH,
-            height: screen_height,
+            x: win_x,
+            y: win_y,
+            width: win_width,
+            height: win_height,
             border_width: 0,
             class: x::WindowClass::InputOutput,
             visual: screen.root_visual(),
             value_list: &[
-                x::Cw::BackPixel(screen.black_pixel()),
+                x::Cw::BackPixel(config.background),
                 x::Cw::OverrideRedirect(true),
                 x::Cw::EventMask(
-                    x::EventMask::EXPOSURE 
-                    | x::EventMask::BUTTON_PRESS 
+                    x::EventMask::EXPOSURE
+                    | x::EventMask::BUTTON_PRESS
                     | x::EventMask::PROPERTY_CHANGE
                 ),
             ],
@@ -92,8 +146,8 @@ impl Panel {
             cid: gc,
             drawable: x::Drawable::Window(window),
             value_list: &[
-                x::Gc::Foreground(screen.black_pixel()),
-                x::Gc::Background(screen.black_pixel()),
+                x::Gc::Foreground(config.background),
+                x::Gc::Background(config.background),
                 x::Gc::GraphicsExposures(false),
             ],
         });
@@ -108,112 +162,415 @@ impl Panel {
             ],
         });
 
-        Panel {
+        // Графический контекст для подсветки активного окна
+        conn.send_request(&x::CreateGc {
+            cid: highlight_gc,
+            drawable: x::Drawable::Window(window),
+            value_list: &[
+                x::Gc::Foreground(config.accent),
+                x::Gc::GraphicsExposures(false),
+            ],
+        });
+
+        set_strut(
+            conn,
+            window,
+            atoms.net_wm_strut_partial,
+            config.edge,
+            (win_x, win_y, win_width, win_height),
+            screen_width,
+            screen_height,
+        );
+
+        conn.send_request(&randr::SelectInput {
+            window: screen.root(),
+            enable: randr::NotifyMask::SCREEN_CHANGE,
+        });
+
+        let mut panel = Panel {
             window,
             gc,
             icon_gc,
-            height: screen_height,
+            highlight_gc,
+            win_width,
+            win_height,
             windows: HashMap::new(),
+            tray_icons: HashMap::new(),
+            tray_selection,
+            current_desktop: DESKTOP_ALL,
+            active: None,
             atoms,
-        }
+            config,
+        };
+
+        panel.acquire_tray_selection(conn, screen.root());
+
+        conn.send_request(&x::ChangeWindowAttributes {
+            window: screen.root(),
+            value_list: &[x::Cw::EventMask(x::EventMask::PROPERTY_CHANGE)],
+        });
+
+        panel.refresh_current_desktop(conn, screen.root());
+        panel.refresh_active_window(conn, screen.root());
+
+        panel
     }
 
-    fn update_windows(&mut self, conn: &Connection) {
-        let root = conn.get_setup().roots().next().unwrap().root();
+    // Re-anchors the panel to the primary output and resizes/repositions its
+    // window and strut, in response to a RandR ScreenChangeNotify (monitor
+    // plugged/unplugged or resolution change).
+    fn handle_screen_change(&mut self, conn: &Connection, root: x::Window, screen_width: u16, screen_height: u16) {
+        let output = primary_output_geometry(conn, root, (0, 0, screen_width, screen_height));
+        let geometry @ (win_x, win_y, win_width, win_height) = panel_geometry(&self.config, output);
+
+        conn.send_request(&x::ConfigureWindow {
+            window: self.window,
+            value_list: &[
+                x::ConfigWindow::X(win_x as i32),
+                x::ConfigWindow::Y(win_y as i32),
+                x::ConfigWindow::Width(win_width as u32),
+                x::ConfigWindow::Height(win_height as u32),
+            ],
+        });
+
+        set_strut(
+            conn,
+            self.window,
+            self.atoms.net_wm_strut_partial,
+            self.config.edge,
+            geometry,
+            screen_width,
+            screen_height,
+        );
+
+        self.win_width = win_width;
+        self.win_height = win_height;
+
+        self.update_layout(conn);
+    }
+
+    // Re-reads _NET_ACTIVE_WINDOW from the root window.
+    fn refresh_active_window(&mut self, conn: &Connection, root: x::Window) {
         let cookie = conn.send_request(&x::GetProperty {
             delete: false,
             window: root,
-            property: self.atoms.net_client_list,
+            property: self.atoms.net_active_window,
             r#type: x::ATOM_WINDOW,
             long_offset: 0,
-            long_length: 8192,
+            long_length: 1,
         });
 
-        if let Ok(reply) = conn.wait_for_reply(cookie) {
-            if reply.format() == 32 {
-                let current_windows: Vec<x::Window> = reply.value::<u32>()
-                    .iter()
-                    .map(|&id| unsafe { x::Window::new(id) })
-                    .collect();
-
-                self.windows.retain(|k, v| current_windows.contains(k) && v.icon_pixmap.is_some());
-                
-                for &window in &current_windows {
-                    if self.should_show_window(conn, window) {
-                        let title = get_window_title(conn, window, &self.atoms);
-                        let icon = load_window_icon(conn, window, self.atoms.net_wm_icon, self.icon_gc);
-                        
-                        if let Some(icon) = icon {
-                            self.windows.entry(window).or_insert(WindowData {
-                                icon_pixmap: Some(icon),
-                                icon_rect: x::Rectangle { x: 0, y: 0, width: 0, height: 0 },
-                                title,
-                            });
-                        }
-                    }
-                }
-                
-                self.update_layout();
-            }
-        }
-    }
-
-    fn should_show_window(&self, conn: &Connection, window: x::Window) -> bool {
-        self.is_normal_window(conn, window) &&
-        !self.is_skip_taskbar(conn, window) &&
-        !self.is_override_redirect(conn, window)
+        self.active = conn
+            .wait_for_reply(cookie)
+            .ok()
+            .and_then(|r| r.value::<u32>().first().copied())
+            .filter(|&id| id != 0)
+            .map(|id| unsafe { x::Window::new(id) });
     }
 
-    fn is_normal_window(&self, conn: &Connection, window: x::Window) -> bool {
+    // Re-reads _NET_CURRENT_DESKTOP from the root window.
+    fn refresh_current_desktop(&mut self, conn: &Connection, root: x::Window) {
         let cookie = conn.send_request(&x::GetProperty {
             delete: false,
-            window,
-            property: self.atoms.net_wm_window_type,
-            r#type: x::ATOM_ATOM,
+            window: root,
+            property: self.atoms.net_current_desktop,
+            r#type: x::ATOM_CARDINAL,
             long_offset: 0,
-            long_length: 32,
+            long_length: 1,
+        });
+
+        self.current_desktop = conn
+            .wait_for_reply(cookie)
+            .ok()
+            .and_then(|r| r.value::<u32>().first().copied())
+            .unwrap_or(DESKTOP_ALL);
+    }
+
+    // Claims the _NET_SYSTEM_TRAY_Sn selection so existing tray clients re-dock into us.
+    fn acquire_tray_selection(&self, conn: &Connection, root: x::Window) {
+        conn.send_request(&x::SetSelectionOwner {
+            owner: self.window,
+            selection: self.tray_selection,
+            time: x::CURRENT_TIME,
+        });
+
+        let event = x::ClientMessageEvent::new(
+            root,
+            self.atoms.manager,
+            x::ClientMessageData::Data32([
+                x::CURRENT_TIME,
+                self.tray_selection.resource_id(),
+                self.window.resource_id(),
+                0,
+                0,
+            ]),
+        );
+        conn.send_request(&x::SendEvent {
+            propagate: false,
+            destination: x::SendEventDest::Window(root),
+            event_mask: x::EventMask::STRUCTURE_NOTIFY,
+            event: &event,
+        });
+        conn.flush().unwrap();
+    }
+
+    // Handles a SYSTEM_TRAY_REQUEST_DOCK opcode: reparents the client into the panel
+    // and completes the XEMBED handshake.
+    fn dock_tray_icon(&mut self, conn: &Connection, client: x::Window) {
+        conn.send_request(&x::ChangeWindowAttributes {
+            window: client,
+            value_list: &[x::Cw::EventMask(
+                x::EventMask::STRUCTURE_NOTIFY | x::EventMask::PROPERTY_CHANGE,
+            )],
+        });
+
+        conn.send_request(&x::ReparentWindow {
+            window: client,
+            parent: self.window,
+            x: 0,
+            y: 0,
+        });
+
+        conn.send_request(&x::ConfigureWindow {
+            window: client,
+            value_list: &[
+                x::ConfigWindow::Width(self.config.icon_size as u32),
+                x::ConfigWindow::Height(self.config.icon_size as u32),
+            ],
+        });
+
+        let notify = x::ClientMessageEvent::new(
+            client,
+            self.atoms.xembed_embedded_notify,
+            x::ClientMessageData::Data32([
+                x::CURRENT_TIME,
+                0,
+                0,
+                self.window.resource_id(),
+                0,
+            ]),
+        );
+        conn.send_request(&x::SendEvent {
+            propagate: false,
+            destination: x::SendEventDest::Window(client),
+            event_mask: x::EventMask::NO_EVENT,
+            event: &notify,
         });
 
-        conn.wait_for_reply(cookie)
-            .map(|r| r.value::<x::Atom>().contains(&self.atoms.net_wm_window_type_normal))
-            .unwrap_or(false)
+        conn.send_request(&x::MapWindow { window: client });
+
+        self.tray_icons.insert(
+            client,
+            TrayIconData {
+                rect: x::Rectangle {
+                    x: 0,
+                    y: 0,
+                    width: self.config.icon_size,
+                    height: self.config.icon_size,
+                },
+            },
+        );
+
+        conn.flush().unwrap();
     }
 
-    fn is_skip_taskbar(&self, conn: &Connection, window: x::Window) -> bool {
+    // Drops a tray icon once its window is unmapped or destroyed. Returns whether
+    // it was actually tracked, so the caller knows whether a relayout is needed.
+    fn undock_tray_icon(&mut self, window: x::Window) -> bool {
+        self.tray_icons.remove(&window).is_some()
+    }
+
+    // Rescans `_NET_CLIENT_LIST`. Only called when that property actually
+    // changes, not on every event. The per-window visibility queries are
+    // pipelined: every one is sent before any reply is awaited, so they cost one
+    // round-trip for the whole client list instead of one per query per window.
+    // The title and icon of each newly tracked window are then fetched one
+    // window at a time, with blocking round-trips.
+    fn update_windows(&mut self, conn: &Connection, root: x::Window) {
         let cookie = conn.send_request(&x::GetProperty {
             delete: false,
-            window,
-            property: self.atoms.net_wm_state_skip_taskbar,
-            r#type: x::ATOM_ATOM,
+            window: root,
+            property: self.atoms.net_client_list,
+            r#type: x::ATOM_WINDOW,
             long_offset: 0,
-            long_length: 32,
+            long_length: 8192,
         });
 
-        conn.wait_for_reply(cookie)
-            .map(|r| !r.value::<x::Atom>().is_empty())
-            .unwrap_or(false)
+        let Ok(reply) = conn.wait_for_reply(cookie) else { return };
+        if reply.format() != 32 {
+            return;
+        }
+
+        let current_windows: Vec<x::Window> = reply.value::<u32>()
+            .iter()
+            .map(|&id| unsafe { x::Window::new(id) })
+            .collect();
+
+        let type_cookies: Vec<_> = current_windows.iter()
+            .map(|&w| conn.send_request(&x::GetProperty {
+                delete: false,
+                window: w,
+                property: self.atoms.net_wm_window_type,
+                r#type: x::ATOM_ATOM,
+                long_offset: 0,
+                long_length: 32,
+            }))
+            .collect();
+        let skip_cookies: Vec<_> = current_windows.iter()
+            .map(|&w| conn.send_request(&x::GetProperty {
+                delete: false,
+                window: w,
+                property: self.atoms.net_wm_state_skip_taskbar,
+                r#type: x::ATOM_ATOM,
+                long_offset: 0,
+                long_length: 32,
+            }))
+            .collect();
+        let attr_cookies: Vec<_> = current_windows.iter()
+            .map(|&w| conn.send_request(&x::GetWindowAttributes { window: w }))
+            .collect();
+        let desktop_cookies: Vec<_> = current_windows.iter()
+            .map(|&w| conn.send_request(&x::GetProperty {
+                delete: false,
+                window: w,
+                property: self.atoms.net_wm_desktop,
+                r#type: x::ATOM_CARDINAL,
+                long_offset: 0,
+                long_length: 1,
+            }))
+            .collect();
+
+        let visible: Vec<bool> = type_cookies.into_iter()
+            .zip(skip_cookies)
+            .zip(attr_cookies)
+            .zip(desktop_cookies)
+            .map(|(((type_c, skip_c), attr_c), desktop_c)| {
+                let is_normal = conn.wait_for_reply(type_c)
+                    .map(|r| r.value::<x::Atom>().contains(&self.atoms.net_wm_window_type_normal))
+                    .unwrap_or(false);
+                let is_skip = conn.wait_for_reply(skip_c)
+                    .map(|r| !r.value::<x::Atom>().is_empty())
+                    .unwrap_or(false);
+                let is_override = conn.wait_for_reply(attr_c)
+                    .map(|r| r.override_redirect())
+                    .unwrap_or(true);
+                let desktop = conn.wait_for_reply(desktop_c)
+                    .ok()
+                    .and_then(|r| r.value::<u32>().first().copied())
+                    .unwrap_or(DESKTOP_ALL);
+                let on_desktop = desktop == DESKTOP_ALL || desktop == self.current_desktop;
+
+                is_normal && !is_skip && !is_override && on_desktop
+            })
+            .collect();
+
+        let keep: HashSet<x::Window> = current_windows.iter()
+            .zip(visible.iter())
+            .filter(|(_, &show)| show)
+            .map(|(&w, _)| w)
+            .collect();
+
+        self.windows.retain(|w, v| keep.contains(w) && v.icon_pixmap.is_some());
+
+        for &window in &keep {
+            if !self.windows.contains_key(&window) {
+                self.track_new_window(conn, window);
+            }
+        }
+
+        self.update_layout(conn);
     }
 
-    fn is_override_redirect(&self, conn: &Connection, window: x::Window) -> bool {
-        conn.wait_for_reply(conn.send_request(&x::GetWindowAttributes { window }))
-            .map(|attrs| attrs.override_redirect())
-            .unwrap_or(true)
+    // Starts tracking a newly-visible window: selects PropertyChange on it (so we
+    // get notified when its title/icon changes) and loads its initial icon/title.
+    fn track_new_window(&mut self, conn: &Connection, window: x::Window) {
+        conn.send_request(&x::ChangeWindowAttributes {
+            window,
+            value_list: &[x::Cw::EventMask(x::EventMask::PROPERTY_CHANGE)],
+        });
+
+        let title = get_window_title(conn, window, &self.atoms);
+        let icon = load_window_icon(
+            conn,
+            window,
+            self.atoms.net_wm_icon,
+            self.icon_gc,
+            self.config.icon_size,
+            self.config.background,
+        );
+
+        if let Some(icon) = icon {
+            self.windows.insert(window, WindowData {
+                icon_pixmap: Some(icon),
+                icon_rect: x::Rectangle { x: 0, y: 0, width: 0, height: 0 },
+                title,
+            });
+        }
     }
 
-    fn update_layout(&mut self) {
-        let mut y_pos = ITEM_SPACING;
-        let x_center = (PANEL_WIDTH as i16 - ICON_SIZE as i16) / 2;
+    // Re-reads a single tracked window's title or icon in response to a
+    // PropertyNotify on that window, instead of rescanning everything.
+    fn refresh_window_property(&mut self, conn: &Connection, window: x::Window, atom: x::Atom) {
+        if !self.windows.contains_key(&window) {
+            return;
+        }
+
+        if atom == self.atoms.net_wm_name {
+            let title = get_window_title(conn, window, &self.atoms);
+            if let Some(data) = self.windows.get_mut(&window) {
+                data.title = title;
+            }
+        } else if atom == self.atoms.net_wm_icon {
+            let icon = load_window_icon(
+                conn,
+                window,
+                self.atoms.net_wm_icon,
+                self.icon_gc,
+                self.config.icon_size,
+                self.config.background,
+            );
+            if let Some(data) = self.windows.get_mut(&window) {
+                data.icon_pixmap = icon;
+            }
+        }
+    }
+
+    fn update_layout(&mut self, conn: &Connection) {
+        let icon_size = self.config.icon_size;
+        let spacing = self.config.spacing;
+        let vertical = self.config.edge.is_vertical();
+        let cross_center = if vertical {
+            (self.win_width as i16 - icon_size as i16) / 2
+        } else {
+            (self.win_height as i16 - icon_size as i16) / 2
+        };
+        let mut main_pos = spacing;
 
         self.windows.retain(|_, v| v.icon_pixmap.is_some());
 
         for data in self.windows.values_mut() {
-            data.icon_rect = x::Rectangle {
-                x: x_center,
-                y: y_pos,
-                width: ICON_SIZE,
-                height: ICON_SIZE,
+            data.icon_rect = if vertical {
+                x::Rectangle { x: cross_center, y: main_pos, width: icon_size, height: icon_size }
+            } else {
+                x::Rectangle { x: main_pos, y: cross_center, width: icon_size, height: icon_size }
             };
-            y_pos += ICON_SIZE as i16 + ITEM_SPACING;
+            main_pos += icon_size as i16 + spacing;
+        }
+
+        for (&window, data) in self.tray_icons.iter_mut() {
+            data.rect = if vertical {
+                x::Rectangle { x: cross_center, y: main_pos, width: icon_size, height: icon_size }
+            } else {
+                x::Rectangle { x: main_pos, y: cross_center, width: icon_size, height: icon_size }
+            };
+            conn.send_request(&x::ConfigureWindow {
+                window,
+                value_list: &[
+                    x::ConfigWindow::X(data.rect.x as i32),
+                    x::ConfigWindow::Y(data.rect.y as i32),
+                ],
+            });
+            main_pos += icon_size as i16 + spacing;
         }
     }
 
@@ -225,13 +582,21 @@ impl Panel {
             rectangles: &[x::Rectangle {
                 x: 0,
                 y: 0,
-                width: PANEL_WIDTH,
-                height: self.height,
+                width: self.win_width,
+                height: self.win_height,
             }],
         });
 
         // Рисование иконок
-        for data in self.windows.values() {
+        for (&window, data) in self.windows.iter() {
+            if Some(window) == self.active {
+                conn.send_request(&x::PolyFillRectangle {
+                    drawable: x::Drawable::Window(self.window),
+                    gc: self.highlight_gc,
+                    rectangles: &[data.icon_rect],
+                });
+            }
+
             if let Some(pixmap) = data.icon_pixmap {
                 conn.send_request(&x::CopyArea {
                     src_drawable: x::Drawable::Pixmap(pixmap),
@@ -251,6 +616,122 @@ impl Panel {
     }
 }
 
+// Computes the panel's window geometry by anchoring `panel_width` to the
+// configured edge of `output` (a single monitor's rect in root coordinates),
+// so the panel only spans that monitor instead of the whole virtual screen.
+fn panel_geometry(config: &Config, output: Rect) -> Rect {
+    let (out_x, out_y, out_width, out_height) = output;
+
+    if config.edge.is_vertical() {
+        let x = if config.edge == Edge::Left {
+            out_x
+        } else {
+            out_x + out_width as i16 - config.panel_width as i16
+        };
+        (x, out_y, config.panel_width, out_height)
+    } else {
+        let y = if config.edge == Edge::Top {
+            out_y
+        } else {
+            out_y + out_height as i16 - config.panel_width as i16
+        };
+        (out_x, y, out_width, config.panel_width)
+    }
+}
+
+// Finds the geometry of the primary RandR output (falling back to the first
+// active CRTC, then to `fallback` if RandR is unavailable or nothing is
+// configured yet).
+fn primary_output_geometry(conn: &Connection, root: x::Window, fallback: Rect) -> Rect {
+    let resources_cookie = conn.send_request(&randr::GetScreenResources { window: root });
+    let Ok(resources) = conn.wait_for_reply(resources_cookie) else {
+        return fallback;
+    };
+
+    let primary_cookie = conn.send_request(&randr::GetOutputPrimary { window: root });
+    let primary_output = conn.wait_for_reply(primary_cookie).ok().map(|r| r.output());
+
+    let crtc = primary_output
+        .and_then(|output| {
+            let cookie = conn.send_request(&randr::GetOutputInfo {
+                output,
+                config_timestamp: resources.config_timestamp(),
+            });
+            conn.wait_for_reply(cookie).ok().map(|r| r.crtc())
+        })
+        .filter(|crtc| crtc.resource_id() != 0);
+
+    for crtc in crtc.into_iter().chain(resources.crtcs().iter().copied()) {
+        let cookie = conn.send_request(&randr::GetCrtcInfo {
+            crtc,
+            config_timestamp: resources.config_timestamp(),
+        });
+
+        if let Ok(info) = conn.wait_for_reply(cookie) {
+            if info.width() > 0 && info.height() > 0 {
+                return (info.x(), info.y(), info.width(), info.height());
+            }
+        }
+    }
+
+    fallback
+}
+
+// Sets _NET_WM_STRUT_PARTIAL so the WM reserves screen space for the panel and
+// doesn't place other windows under it. The start/end pair for the panel's edge
+// is restricted to the panel's own span, so on multi-monitor setups the
+// reservation doesn't bleed into other outputs.
+//
+// _NET_WM_STRUT_PARTIAL can only express a reservation measured from the edge
+// of the *root* window, so it has no way to describe "reserve this strip on
+// just one monitor" when that monitor doesn't itself touch the corresponding
+// screen edge. In that case we emit an all-zero (no-op) strut rather than
+// reserving a bogus region that reaches across the other monitors.
+fn set_strut(
+    conn: &Connection,
+    window: x::Window,
+    atom: x::Atom,
+    edge: Edge,
+    geometry: Rect,
+    screen_width: u16,
+    screen_height: u16,
+) {
+    let (win_x, win_y, win_width, win_height) = geometry;
+    let mut strut = [0u32; 12];
+
+    match edge {
+        Edge::Left if win_x == 0 => {
+            strut[0] = (win_x + win_width as i16) as u32;
+            strut[4] = win_y as u32;
+            strut[5] = (win_y + win_height as i16 - 1) as u32;
+        }
+        Edge::Right if win_x + win_width as i16 == screen_width as i16 => {
+            strut[1] = (screen_width as i16 - win_x) as u32;
+            strut[6] = win_y as u32;
+            strut[7] = (win_y + win_height as i16 - 1) as u32;
+        }
+        Edge::Top if win_y == 0 => {
+            strut[2] = (win_y + win_height as i16) as u32;
+            strut[8] = win_x as u32;
+            strut[9] = (win_x + win_width as i16 - 1) as u32;
+        }
+        Edge::Bottom if win_y + win_height as i16 == screen_height as i16 => {
+            strut[3] = (screen_height as i16 - win_y) as u32;
+            strut[10] = win_x as u32;
+            strut[11] = (win_x + win_width as i16 - 1) as u32;
+        }
+        _ => {}
+    }
+
+    conn.send_request(&x::ChangeProperty {
+        mode: x::PropMode::Replace,
+        window,
+        property: atom,
+        r#type: x::ATOM_CARDINAL,
+        data: &strut,
+    });
+}
+
 fn intern_atom(conn: &Connection, name: &[u8]) -> x::Atom {
     conn.wait_for_reply(conn.send_request(&x::InternAtom {
         only_if_exists: true,
@@ -258,6 +739,15 @@ fn intern_atom(conn: &Connection, name: &[u8]) -> x::Atom {
     })).map(|r| r.atom()).unwrap_or(x::ATOM_NONE)
 }
 
+// Like `intern_atom`, but creates the atom if it doesn't exist yet. Needed for
+// selections like `_NET_SYSTEM_TRAY_Sn` that we are the first to claim.
+fn intern_atom_create(conn: &Connection, name: &[u8]) -> x::Atom {
+    conn.wait_for_reply(conn.send_request(&x::InternAtom {
+        only_if_exists: false,
+        name,
+    })).map(|r| r.atom()).unwrap_or(x::ATOM_NONE)
+}
+
 fn get_window_title(conn: &Connection, window: x::Window, atoms: &Atoms) -> String {
     let cookie = conn.send_request(&x::GetProperty {
         delete: false,
@@ -282,6 +772,8 @@ fn load_window_icon(
     window: x::Window,
     atom: x::Atom,
     icon_gc: x::Gcontext,
+    icon_size: u16,
+    background: u32,
 ) -> Option<x::Pixmap> {
     let cookie = conn.send_request(&x::GetProperty {
         delete: false,
@@ -312,31 +804,64 @@ fn load_window_icon(
         return None;
     }
 
-    let (width, height, icon_data) = find_best_icon(raw_data)?;
+    let (width, height, icon_data) = find_best_icon(raw_data, icon_size)?;
     let screen = conn.get_setup().roots().next()?;
-    
+
     let pixmap = conn.generate_id();
     conn.send_request(&x::CreatePixmap {
         depth: 24,
         pid: pixmap,
         drawable: x::Drawable::Window(screen.root()),
-        width: ICON_SIZE,
-        height: ICON_SIZE,
+        width: icon_size,
+        height: icon_size,
     });
 
-    let mut pixels = Vec::with_capacity(ICON_SIZE as usize * ICON_SIZE as usize * 4);
-    for y in 0..ICON_SIZE {
-        for x in 0..ICON_SIZE {
-            let src_x = (x as f32 * width as f32 / ICON_SIZE as f32) as usize;
-            let src_y = (y as f32 * height as f32 / ICON_SIZE as f32) as usize;
-            let idx = src_y * width + src_x;
-            
-            let pixel = *icon_data.get(idx).unwrap_or(&0);
+    let bg = (
+        ((background >> 16) & 0xFF) as f32,
+        ((background >> 8) & 0xFF) as f32,
+        (background & 0xFF) as f32,
+    );
+
+    let mut pixels = Vec::with_capacity(icon_size as usize * icon_size as usize * 4);
+    for y in 0..icon_size {
+        for x in 0..icon_size {
+            let src_x0 = (x as usize * width) / icon_size as usize;
+            let src_x1 = (((x + 1) as usize * width) / icon_size as usize).max(src_x0 + 1).min(width);
+            let src_y0 = (y as usize * height) / icon_size as usize;
+            let src_y1 = (((y + 1) as usize * height) / icon_size as usize).max(src_y0 + 1).min(height);
+
+            let (mut r, mut g, mut b, mut a) = (0f32, 0f32, 0f32, 0f32);
+            let mut samples = 0f32;
+
+            for sy in src_y0..src_y1 {
+                for sx in src_x0..src_x1 {
+                    let pixel = *icon_data.get(sy * width + sx).unwrap_or(&0);
+                    let alpha = ((pixel >> 24) & 0xFF) as f32 / 255.0;
+                    r += ((pixel >> 16) & 0xFF) as f32 * alpha;
+                    g += ((pixel >> 8) & 0xFF) as f32 * alpha;
+                    b += (pixel & 0xFF) as f32 * alpha;
+                    a += alpha;
+                    samples += 1.0;
+                }
+            }
+
+            if samples > 0.0 {
+                r /= samples;
+                g /= samples;
+                b /= samples;
+                a /= samples;
+            }
+
+            // Composite the averaged premultiplied color over the panel background.
+            let out_r = r + bg.0 * (1.0 - a);
+            let out_g = g + bg.1 * (1.0 - a);
+            let out_b = b + bg.2 * (1.0 - a);
+
             pixels.extend_from_slice(&[
-                ((pixel >> 16) & 0xFF) as u8, // R
-                ((pixel >> 8) & 0xFF) as u8,  // G
-                (pixel & 0xFF) as u8,         // B
-                0xFF,                         // A
+                out_r.round().clamp(0.0, 255.0) as u8, // R
+                out_g.round().clamp(0.0, 255.0) as u8, // G
+                out_b.round().clamp(0.0, 255.0) as u8, // B
+                0xFF,                                   // A
             ]);
         }
     }
@@ -345,8 +870,8 @@ fn load_window_icon(
         format: x::ImageFormat::ZPixmap,
         drawable: x::Drawable::Pixmap(pixmap),
         gc: icon_gc,
-        width: ICON_SIZE,
-        height: ICON_SIZE,
+        width: icon_size,
+        height: icon_size,
         dst_x: 0,
         dst_y: 0,
         left_pad: 0,
@@ -357,37 +882,51 @@ fn load_window_icon(
     Some(pixmap)
 }
 
-fn find_best_icon(data: &[u32]) -> Option<(usize, usize, &[u32])> {
-    let mut best_size = 0;
-    let mut best_icon = None;
+// Prefers the smallest icon that is still >= `icon_size` in both dimensions, so we
+// downscale as little as possible. Falls back to the largest available icon if
+// none is big enough (we'll have to upscale it instead). Icons wider or taller
+// than 256 pixels are skipped entirely.
+fn find_best_icon(data: &[u32], icon_size: u16) -> Option<(usize, usize, &[u32])> {
+    let mut best_fit: Option<(usize, usize, &[u32])> = None;
+    let mut largest: Option<(usize, usize, &[u32])> = None;
     let mut offset = 0;
 
     while offset + 1 < data.len() {
         let width = data[offset] as usize;
         let height = data[offset + 1] as usize;
-        let icon_size = width * height;
-        let required_length = offset + 2 + icon_size;
+        let pixel_count = width * height;
+        let required_length = offset + 2 + pixel_count;
 
         if required_length > data.len() {
             break;
         }
 
-        if icon_size > best_size && width <= 256 && height <= 256 {
-            best_size = icon_size;
-            best_icon = Some((width, height, &data[offset + 2..required_length]));
+        if width <= 256 && height <= 256 {
+            let icon = (width, height, &data[offset + 2..required_length]);
+
+            if width >= icon_size as usize && height >= icon_size as usize
+                && best_fit.is_none_or(|(bw, bh, _)| pixel_count < bw * bh)
+            {
+                best_fit = Some(icon);
+            }
+
+            if largest.is_none_or(|(lw, lh, _)| pixel_count > lw * lh) {
+                largest = Some(icon);
+            }
         }
 
         offset = required_length;
     }
 
-    best_icon
+    best_fit.or(largest)
 }
 
 fn main() {
-    let (conn, screen_num) = Connection::connect(None).unwrap();
+    let (conn, screen_num) =
+        Connection::connect_with_extensions(None, &[xcb::Extension::RandR], &[]).unwrap();
     let screen = &conn.get_setup().roots().nth(screen_num as usize).unwrap();
-    
-    let mut panel = Panel::new(&conn, screen);
+
+    let mut panel = Panel::new(&conn, screen, screen_num);
     
     conn.send_request(&x::MapWindow { window: panel.window });
     conn.flush().unwrap();
@@ -401,10 +940,10 @@ fn main() {
         data: &[panel.atoms.wm_delete],
     });
 
-    loop {
-        panel.update_windows(&conn);
-        panel.redraw(&conn);
+    panel.update_windows(&conn, screen.root());
+    panel.redraw(&conn);
 
+    loop {
         if let Ok(event) = conn.wait_for_event() {
             match event {
                 xcb::Event::X(x::Event::Expose(ev)) => {
@@ -425,7 +964,7 @@ fn main() {
                                 && y <= rect.y + rect.height as i16 
                             {
                                 println!("Switching to window: {} (ID: {:?})", data.title, win);
-                                focus_window(&conn, *win);
+                                focus_window(&conn, *win, &panel.atoms, screen.root(), panel.window);
                             }
                         }
                     }
@@ -437,6 +976,47 @@ fn main() {
                             break;
                         }
                     }
+
+                    if ev.r#type() == panel.atoms.net_system_tray_opcode {
+                        if let ClientMessageData::Data32(data) = ev.data() {
+                            if data[1] == SYSTEM_TRAY_REQUEST_DOCK {
+                                let client = unsafe { x::Window::new(data[2]) };
+                                panel.dock_tray_icon(&conn, client);
+                                panel.update_layout(&conn);
+                                panel.redraw(&conn);
+                            }
+                        }
+                    }
+                }
+                xcb::Event::X(x::Event::DestroyNotify(ev)) if panel.undock_tray_icon(ev.window()) => {
+                    panel.update_layout(&conn);
+                    panel.redraw(&conn);
+                }
+                xcb::Event::X(x::Event::UnmapNotify(ev)) if panel.undock_tray_icon(ev.window()) => {
+                    panel.update_layout(&conn);
+                    panel.redraw(&conn);
+                }
+                xcb::Event::X(x::Event::PropertyNotify(ev)) => {
+                    if ev.window() == screen.root() {
+                        if ev.atom() == panel.atoms.net_client_list {
+                            panel.update_windows(&conn, screen.root());
+                            panel.redraw(&conn);
+                        } else if ev.atom() == panel.atoms.net_current_desktop {
+                            panel.refresh_current_desktop(&conn, screen.root());
+                            panel.update_windows(&conn, screen.root());
+                            panel.redraw(&conn);
+                        } else if ev.atom() == panel.atoms.net_active_window {
+                            panel.refresh_active_window(&conn, screen.root());
+                            panel.redraw(&conn);
+                        }
+                    } else if ev.atom() == panel.atoms.net_wm_name || ev.atom() == panel.atoms.net_wm_icon {
+                        panel.refresh_window_property(&conn, ev.window(), ev.atom());
+                        panel.redraw(&conn);
+                    }
+                }
+                xcb::Event::RandR(randr::Event::ScreenChangeNotify(ev)) => {
+                    panel.handle_screen_change(&conn, screen.root(), ev.width(), ev.height());
+                    panel.redraw(&conn);
                 }
                 _ => {}
             }
@@ -447,20 +1027,61 @@ fn main() {
     conn.flush().unwrap();
 }
 
-fn focus_window(conn: &Connection, window: x::Window) {
+// Checks the root window's _NET_SUPPORTED list for _NET_ACTIVE_WINDOW, since
+// interning the atom name only proves some client has heard of it, not that
+// the running WM implements it.
+fn wm_supports_active_window(conn: &Connection, atoms: &Atoms, root: x::Window) -> bool {
+    if atoms.net_active_window == x::ATOM_NONE || atoms.net_supported == x::ATOM_NONE {
+        return false;
+    }
+
+    let cookie = conn.send_request(&x::GetProperty {
+        delete: false,
+        window: root,
+        property: atoms.net_supported,
+        r#type: x::ATOM_ATOM,
+        long_offset: 0,
+        long_length: u32::MAX,
+    });
+
+    conn.wait_for_reply(cookie)
+        .map(|r| r.value::<x::Atom>().contains(&atoms.net_active_window))
+        .unwrap_or(false)
+}
+
+// Activates `window` the EWMH way: ask the window manager to do it via a
+// _NET_ACTIVE_WINDOW client message, so it raises the window, focuses it and
+// switches to its virtual desktop. Falls back to a raw SetInputFocus when the
+// WM doesn't advertise _NET_ACTIVE_WINDOW support.
+fn focus_window(conn: &Connection, window: x::Window, atoms: &Atoms, root: x::Window, source: x::Window) {
     let cookie = conn.send_request(&x::GetWindowAttributes { window });
     if let Ok(attrs) = conn.wait_for_reply(cookie) {
         if !attrs.override_redirect() && attrs.map_state() == x::MapState::Viewable {
-            conn.send_request(&x::SetInputFocus {
-                revert_to: x::InputFocus::PointerRoot,
-                focus: window,
-                time: x::CURRENT_TIME,
-            });
-            
-            conn.send_request(&x::ConfigureWindow {
-                window,
-                value_list: &[x::ConfigWindow::StackMode(x::StackMode::Above)],
-            });
+            if wm_supports_active_window(conn, atoms, root) {
+                let event = x::ClientMessageEvent::new(
+                    window,
+                    atoms.net_active_window,
+                    x::ClientMessageData::Data32([2, x::CURRENT_TIME, source.resource_id(), 0, 0]),
+                );
+                conn.send_request(&x::SendEvent {
+                    propagate: false,
+                    destination: x::SendEventDest::Window(root),
+                    event_mask: x::EventMask::SUBSTRUCTURE_NOTIFY
+                        | x::EventMask::SUBSTRUCTURE_REDIRECT,
+                    event: &event,
+                });
+            } else {
+                conn.send_request(&x::SetInputFocus {
+                    revert_to: x::InputFocus::PointerRoot,
+                    focus: window,
+                    time: x::CURRENT_TIME,
+                });
+
+                conn.send_request(&x::ConfigureWindow {
+                    window,
+                    value_list: &[x::ConfigWindow::StackMode(x::StackMode::Above)],
+                });
+            }
             conn.flush().unwrap();
         }
     }